@@ -0,0 +1,161 @@
+//! Generates a standalone `.rs` module that embeds the result of [`crate::enumerate`] (or
+//! [`crate::enumerate_resolved`]) into the final binary, for runtime introspection.
+//!
+//! The generated module has no dependency on this crate - it only uses `&'static str`/slices and
+//! plain `Copy` enums, so it can be `include!`d from `OUT_DIR` without adding this crate (or
+//! `cargo_toml`/`semver`) to the final binary's dependency graph.
+
+use crate::{ActiveDependency, DepKind, DepSource, GitRev};
+
+/// Render `deps` as Rust source defining `pub const DEPENDENCIES` plus small runtime-only mirrors
+/// of [`crate::ActiveDependency`] and friends.
+///
+/// Intended to be written to `OUT_DIR` from a build script and pulled in with
+/// `include!(concat!(env!("OUT_DIR"), "/dependencies.rs"));`
+pub fn codegen(deps: &std::collections::HashMap<(String, DepKind), ActiveDependency>) -> String
+{
+    let mut out = String::new();
+    out.push_str(HEADER);
+
+    out.push_str(&format!("/// Target triple this crate was built for\npub const TARGET: &str = {:?};\n\n", std::env::var("TARGET").unwrap_or_default()));
+    out.push_str(&format!("/// Cargo profile this crate was built with (`debug` or `release`)\npub const PROFILE: &str = {:?};\n\n", std::env::var("PROFILE").unwrap_or_default()));
+
+    out.push_str("/// Cargo features that were active for this crate when it was built\n");
+    out.push_str("pub const FEATURES: &[&str] = &[\n");
+    for f in active_features()
+    {
+        out.push_str(&format!("    {:?},\n", f));
+    }
+    out.push_str("];\n\n");
+
+    out.push_str("/// All dependencies enumerated at build time, keyed by crate name and `DepKind`\n");
+    out.push_str("pub const DEPENDENCIES: &[(&str, DepInfo)] = &[\n");
+    let mut sorted: Vec<_> = deps.iter().collect();
+    sorted.sort_by(|((an, ak), _), ((bn, bk), _)| an.cmp(bn).then_with(|| format!("{:?}", ak).cmp(&format!("{:?}", bk))));
+    for ((name, _kind), ad) in sorted
+    {
+        out.push_str(&format!("    ({:?}, {}),\n", name, render_depinfo(ad)));
+    }
+    out.push_str("];\n");
+    out
+}
+
+/// Generate `out_path` from `deps`, in the same form as [`codegen`]
+pub fn write_build_module(deps: &std::collections::HashMap<(String, DepKind), ActiveDependency>, out_path: &std::path::Path) -> std::io::Result<()>
+{
+    std::fs::write(out_path, codegen(deps))
+}
+
+/// Cargo doesn't expose the original (possibly dashed, mixed-case) feature names at build time,
+/// only `CARGO_FEATURE_<UPPER_SNAKE>` env vars - report those as lowercase, dash-free names.
+fn active_features() -> Vec<String>
+{
+    let mut v: Vec<String> = std::env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase()))
+        .collect();
+    v.sort();
+    v
+}
+
+fn render_depinfo(ad: &ActiveDependency) -> String
+{
+    let mut features: Vec<&str> = ad.features.iter().map(String::as_str).collect();
+    features.sort();
+    let features = features.iter().map(|f| format!("{:?}", f)).collect::<Vec<_>>().join(", ");
+    format!(
+        "DepInfo {{ kind: {}, source: {}, include_default_features: {:?}, features: &[{}], resolved_version: {}, checksum: {} }}",
+        render_kind(ad.kind),
+        render_source(&ad.source),
+        ad.include_default_features,
+        features,
+        render_opt_str(ad.resolved_version.as_ref().map(|v| v.to_string())),
+        render_opt_str(ad.checksum.clone()),
+        )
+}
+
+fn render_kind(kind: DepKind) -> &'static str
+{
+    match kind
+    {
+    DepKind::Normal => "DepKind::Normal",
+    DepKind::Dev => "DepKind::Dev",
+    DepKind::Build => "DepKind::Build",
+    }
+}
+
+fn render_source(source: &DepSource) -> String
+{
+    match source
+    {
+    DepSource::Git { url, revision } => format!("DepSource::Git {{ url: {:?}, revision: {} }}", url, render_gitrev(revision)),
+    DepSource::Path(p) => format!("DepSource::Path({:?})", p),
+    DepSource::CratesIo(v) => format!("DepSource::CratesIo({:?})", v),
+    DepSource::Unknown => "DepSource::Unknown".to_string(),
+    }
+}
+
+fn render_gitrev(rev: &GitRev) -> String
+{
+    match rev
+    {
+    GitRev::Master => "GitRev::Master".to_string(),
+    GitRev::Branch(b) => format!("GitRev::Branch({:?})", b),
+    GitRev::Tag(t) => format!("GitRev::Tag({:?})", t),
+    GitRev::Revision(r) => format!("GitRev::Revision({:?})", r),
+    }
+}
+
+fn render_opt_str(v: Option<String>) -> String
+{
+    match v
+    {
+    Some(s) => format!("Some({:?})", s),
+    None => "None".to_string(),
+    }
+}
+
+const HEADER: &str = "\
+// @generated by `cargo_dependencies::codegen` - do not edit by hand
+
+/// Which manifest table a dependency was declared in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepKind { Normal, Dev, Build }
+
+/// Where a dependency's source code comes from
+#[derive(Debug, Clone, Copy)]
+pub enum DepSource {
+    /// From git
+    Git { url: &'static str, revision: GitRev },
+    /// Path dependency
+    Path(&'static str),
+    /// A crates.io dependency
+    CratesIo(&'static str),
+    /// The source isn't known
+    Unknown,
+}
+
+/// Which revision of a git dependency to use
+#[derive(Debug, Clone, Copy)]
+pub enum GitRev {
+    /// No specified source, fetches from HEAD of master
+    Master,
+    /// Fetch from HEAD of the given branch
+    Branch(&'static str),
+    /// Fetch the given tag
+    Tag(&'static str),
+    /// Fetch a specific revision
+    Revision(&'static str),
+}
+
+/// A single dependency, as captured at build time
+#[derive(Debug, Clone, Copy)]
+pub struct DepInfo {
+    pub kind: DepKind,
+    pub source: DepSource,
+    pub include_default_features: bool,
+    pub features: &'static [&'static str],
+    pub resolved_version: Option<&'static str>,
+    pub checksum: Option<&'static str>,
+}
+
+";
@@ -4,16 +4,41 @@
 
 use std::collections::{HashMap, HashSet};
 
+pub mod codegen;
+pub use codegen::write_build_module;
+
 #[derive(Debug)]
 /// A cargo depdencency
 pub struct ActiveDependency
 {
+    /// Which manifest table this dependency was declared in
+    pub kind: DepKind,
     /// Source for the dependency code
     pub source: DepSource,
     /// Are default features included
+    ///
+    /// Known limitation: for a `workspace = true` dependency, this is always the workspace's own
+    /// `default-features` setting. `cargo_toml` 0.15's `InheritedDependencyDetail` has no
+    /// `default_features` field, so a member-level `default-features = false` override can't be
+    /// observed here and is silently not honoured.
     pub include_default_features: bool,
     /// Set of explicitly enabled features
     pub features: HashSet<String>,
+    /// Exact version that Cargo resolved for this dependency (only populated by [`enumerate_resolved`])
+    pub resolved_version: Option<semver::Version>,
+    /// Registry checksum recorded in `Cargo.lock` for this dependency (only populated by [`enumerate_resolved`])
+    pub checksum: Option<String>,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Which manifest table a dependency was declared in
+pub enum DepKind
+{
+    /// A `[dependencies]` entry
+    Normal,
+    /// A `[dev-dependencies]` entry
+    Dev,
+    /// A `[build-dependencies]` entry
+    Build,
 }
 #[derive(Debug)]
 pub enum DepSource
@@ -48,51 +73,36 @@ pub enum GitRev
 /// Enumerate all dependencies that are currently available to the crate
 ///
 /// This obtains all unconditional dependencies AND all enabled conditional deps (based on features
-/// and targets)
-pub fn enumerate() -> HashMap<String, ActiveDependency>
+/// and targets), from the `[dependencies]`, `[dev-dependencies]` and `[build-dependencies]`
+/// tables (and their per-target equivalents). The same crate name can appear with a different
+/// `DepKind` (and different features) in more than one table, hence the compound map key.
+///
+/// Known limitation: a `workspace = true` dependency's [`ActiveDependency::include_default_features`]
+/// always reflects the workspace's own setting - a member-level `default-features = false`
+/// override can't currently be observed (see the field's docs for why).
+pub fn enumerate() -> HashMap<(String, DepKind), ActiveDependency>
 {
-    let manifest_path = {
-        let mut p = std::path::PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
-        p.push("Cargo.toml");
-        p
-        };
-    let content = match std::fs::read(&manifest_path)
-        {
-        Ok(v) => v,
-        Err(e) => panic!("Unable to open {}: {:?}", manifest_path.display(), e),
-        };
-    let m = match cargo_toml::Manifest::from_slice(&content)
-        {
-        Ok(v) => v,
-        Err(e) => panic!("Unable to parse {}: {:?}", manifest_path.display(), e),
-        };
-    
-    // Enumerate which of the declared features are active (activates dependency features)
-    let mut dep_features = HashMap::<String, HashSet<String>>::new();
-    for (feat_name, subfeats) in m.features
+    let manifest_dir = std::path::PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let m = load_manifest(&manifest_dir.join("Cargo.toml"));
+    let workspace_deps = find_workspace_dependencies(&manifest_dir, &m);
+
+    // Resolve the `[features]` graph against the active `CARGO_FEATURE_*` flags, to find which
+    // optional dependencies are activated (via `dep:name` or `name/feat`) and what additional
+    // per-dependency features apply (including weak `name?/feat`)
+    let resolved_features = resolve_features(&m.features);
+
+    let mut rv = HashMap::new();
+    for (kind, deps) in [ (DepKind::Normal, &m.dependencies), (DepKind::Dev, &m.dev_dependencies), (DepKind::Build, &m.build_dependencies) ]
     {
-        if std::env::var_os(format!("CARGO_FEATURE_{}", feat_name)).is_some()
+        for (depname, dep_info) in deps
         {
-            for subfeat_desc in subfeats
+            if let Some(ad) = get_activedep(&resolved_features, &workspace_deps, kind, depname, dep_info)
             {
-                let (dep, f) = {
-                    let mut it = subfeat_desc.split('/');
-                    ( it.next().unwrap(), it.next().unwrap(), )
-                    };
-                dep_features.entry(dep.to_string()).or_default().insert(f.to_string());
+                rv.insert((depname.clone(), kind), ad);
             }
         }
     }
-    
-    let mut rv = HashMap::new();
-    for (depname, dep_info) in m.dependencies
-    {
-        if let Some(ad) = get_activedep(&dep_features, &depname, &dep_info)
-        {
-            rv.insert(depname.clone(), ad);
-        }
-    }
-    
+
     let current_target = std::env::var("TARGET").unwrap();
     for (target_name, target_info) in m.target
     {
@@ -116,93 +126,408 @@ pub fn enumerate() -> HashMap<String, ActiveDependency>
         // If this target applies, enumerate dependencies
         if active
         {
-            for (depname, dep_info) in target_info.dependencies
+            for (kind, deps) in [ (DepKind::Normal, &target_info.dependencies), (DepKind::Dev, &target_info.dev_dependencies), (DepKind::Build, &target_info.build_dependencies) ]
             {
-                if let Some(ad) = get_activedep(&dep_features, &depname, &dep_info)
+                for (depname, dep_info) in deps
                 {
-                    rv.insert(depname.clone(), ad);
+                    if let Some(ad) = get_activedep(&resolved_features, &workspace_deps, kind, depname, dep_info)
+                    {
+                        rv.insert((depname.clone(), kind), ad);
+                    }
                 }
             }
         }
     }
-    
+
     rv
 }
 
+/// Like [`enumerate`], but also cross-references `Cargo.lock` to fill in the concrete version
+/// that Cargo actually resolved for each dependency (and its registry checksum, if any) - the
+/// requirement string alone (e.g. `"^1.0"`) doesn't say what shipped.
+pub fn enumerate_resolved() -> HashMap<(String, DepKind), ActiveDependency>
+{
+    let manifest_dir = std::path::PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let m = load_manifest(&manifest_dir.join("Cargo.toml"));
+    let lock_root = locate_workspace(&manifest_dir, &m).map(|(dir, _)| dir).unwrap_or_else(|| manifest_dir.clone());
+    let locked = read_lockfile(&lock_root.join("Cargo.lock"));
+
+    let mut rv = enumerate();
+    for ((depname, _kind), ad) in rv.iter_mut()
+    {
+        let candidates = match locked.get(depname)
+            {
+            Some(v) => v,
+            None => continue,
+            };
+        if let Some(pkg) = pick_locked_package(depname, ad, candidates)
+        {
+            ad.resolved_version = semver::Version::parse(&pkg.version).ok();
+            ad.checksum = pkg.checksum.clone();
+        }
+    }
+    rv
+}
+
+/// Does a `Cargo.lock` package's `source` field correspond to the manifest's declared `DepSource`?
+fn lock_source_matches(source: &DepSource, lock_source: Option<&str>) -> bool
+{
+    match (source, lock_source)
+    {
+    (DepSource::CratesIo(_), Some(s)) => s.starts_with("registry+"),
+    (DepSource::Git { url, .. }, Some(s)) => s.starts_with("git+") && s.contains(url.as_str()),
+    (DepSource::Path(_), None) => true,
+    _ => false,
+    }
+}
+
+/// Pick the `Cargo.lock` entry that corresponds to `ad` out of all entries sharing its name
+///
+/// First narrows by `source` (same name pulled from different registries/git remotes), then - if
+/// that's still ambiguous, e.g. two resolved versions of the same crates.io dependency present at
+/// once in the lockfile - by the first entry whose version satisfies the manifest's requirement.
+/// If neither narrows it down, arbitrarily picks the first remaining candidate and prints a
+/// warning to stderr, so the unchecked choice isn't made silently.
+fn pick_locked_package<'a>(depname: &str, ad: &ActiveDependency, candidates: &'a [LockedPackage]) -> Option<&'a LockedPackage>
+{
+    let by_source: Vec<&LockedPackage> = candidates.iter().filter(|c| lock_source_matches(&ad.source, c.source.as_deref())).collect();
+    let narrowed: Vec<&LockedPackage> = if by_source.is_empty() { candidates.iter().collect() } else { by_source };
+
+    if narrowed.len() <= 1
+    {
+        return narrowed.into_iter().next();
+    }
+
+    if let DepSource::CratesIo(req_str) = &ad.source
+    {
+        if let Ok(req) = semver::VersionReq::parse(req_str)
+        {
+            if let Some(pkg) = narrowed.iter().find(|c| semver::Version::parse(&c.version).map(|v| req.matches(&v)).unwrap_or(false))
+            {
+                return Some(pkg);
+            }
+        }
+    }
+
+    let pkg = narrowed[0];
+    eprintln!("Multiple Cargo.lock entries for {:?} - none uniquely matched by source or version requirement, arbitrarily using {}", depname, pkg.version);
+    Some(pkg)
+}
+
+#[derive(serde::Deserialize)]
+struct CargoLock
+{
+    #[serde(rename = "package", default)]
+    packages: Vec<LockedPackage>,
+}
+#[derive(serde::Deserialize)]
+struct LockedPackage
+{
+    name: String,
+    version: String,
+    #[serde(default)]
+    source: Option<String>,
+    #[serde(default)]
+    checksum: Option<String>,
+}
+
+/// Parse `Cargo.lock` at `lock_path` and index its resolved packages by name (a name can appear
+/// more than once if it's pulled from multiple sources)
+fn read_lockfile(lock_path: &std::path::Path) -> HashMap<String, Vec<LockedPackage>>
+{
+    let content = match std::fs::read_to_string(lock_path)
+        {
+        Ok(v) => v,
+        Err(_) => return HashMap::new(),
+        };
+    let lock: CargoLock = match toml::from_str(&content)
+        {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Unable to parse {}: {:?}", lock_path.display(), e);
+            return HashMap::new();
+            },
+        };
+    let mut by_name = HashMap::<String, Vec<LockedPackage>>::new();
+    for pkg in lock.packages
+    {
+        by_name.entry(pkg.name.clone()).or_default().push(pkg);
+    }
+    by_name
+}
+
+/// Read and parse a `Cargo.toml` manifest, panicking with a useful message on failure
+fn load_manifest(manifest_path: &std::path::Path) -> cargo_toml::Manifest
+{
+    let content = match std::fs::read(manifest_path)
+        {
+        Ok(v) => v,
+        Err(e) => panic!("Unable to open {}: {:?}", manifest_path.display(), e),
+        };
+    match cargo_toml::Manifest::from_slice(&content)
+        {
+        Ok(v) => v,
+        Err(e) => panic!("Unable to parse {}: {:?}", manifest_path.display(), e),
+        }
+}
+
+/// Find the ancestor `Cargo.toml` that defines `[workspace]` for the crate at `manifest_dir`,
+/// honouring an explicit `package.workspace` path. Returns the directory containing it, along
+/// with the parsed manifest.
+fn locate_workspace(manifest_dir: &std::path::Path, own_manifest: &cargo_toml::Manifest) -> Option<(std::path::PathBuf, cargo_toml::Manifest)>
+{
+    let try_load = |dir: &std::path::Path| -> Option<cargo_toml::Manifest> {
+        let content = std::fs::read(dir.join("Cargo.toml")).ok()?;
+        cargo_toml::Manifest::from_slice(&content).ok()
+        };
+
+    // The common single-repo layout: the crate's own manifest IS the workspace root (it has both
+    // `[package]` and `[workspace]`) - no need to walk parents to find its own `[workspace]` table.
+    if own_manifest.workspace.is_some()
+    {
+        return Some((manifest_dir.to_path_buf(), own_manifest.clone()));
+    }
+
+    if let Some(ws_path) = own_manifest.package.as_ref().and_then(|p| p.workspace.as_ref())
+    {
+        let dir = manifest_dir.join(ws_path);
+        return try_load(&dir).map(|m| (dir, m));
+    }
+
+    let mut dir = manifest_dir.to_path_buf();
+    while dir.pop()
+    {
+        if let Some(m) = try_load(&dir)
+        {
+            if m.workspace.is_some()
+            {
+                return Some((dir, m));
+            }
+        }
+    }
+    None
+}
+
+/// Locate the `[workspace.dependencies]` table (if any) that applies to the crate at `manifest_dir`
+fn find_workspace_dependencies(manifest_dir: &std::path::Path, own_manifest: &cargo_toml::Manifest) -> std::collections::BTreeMap<String, cargo_toml::Dependency>
+{
+    match locate_workspace(manifest_dir, own_manifest).and_then(|(_, m)| m.workspace)
+    {
+    Some(ws) => ws.dependencies,
+    None => std::collections::BTreeMap::new(),
+    }
+}
+
 /// Get an "ActiveDependency" for this `cargo_toml` dependency
-fn get_activedep(dep_features: &HashMap<String, HashSet<String>>, depname: &str, dep_info: &cargo_toml::Dependency) -> Option<ActiveDependency>
+fn get_activedep(resolved_features: &ResolvedFeatures, workspace_deps: &std::collections::BTreeMap<String, cargo_toml::Dependency>, kind: DepKind, depname: &str, dep_info: &cargo_toml::Dependency) -> Option<ActiveDependency>
 {
     Some(match dep_info
     {
     cargo_toml::Dependency::Simple(version_str) => {
         ActiveDependency {
+            kind,
             source: DepSource::CratesIo(version_str.clone()),
             include_default_features: true,
-            features: dep_features.get(depname).cloned().unwrap_or(HashSet::new()),
+            features: resolved_features.dep_features(depname),
+            resolved_version: None,
+            checksum: None,
             }
         },
     cargo_toml::Dependency::Inherited(details) => {
-        if details.optional && std::env::var_os(format!("CARGO_FEATURE_{}", depname)).is_none() {
+        if details.optional && !resolved_features.is_dep_active(depname) {
             return None;
         }
-		// Cannot get the full source without workspace info
-        let source = DepSource::Unknown;
-        let mut features = dep_features.get(depname).cloned().unwrap_or(HashSet::new());
+        // Resolve against `[workspace.dependencies]` - that entry supplies the real source and
+        // the baseline default-features/features, which this member entry then layers on top of.
+        let (source, ws_default_features, ws_features) = match workspace_deps.get(depname)
+            {
+            Some(cargo_toml::Dependency::Simple(version_str)) => (DepSource::CratesIo(version_str.clone()), true, HashSet::new()),
+            Some(cargo_toml::Dependency::Detailed(wd)) => (detailed_source(wd), wd.default_features, wd.features.iter().cloned().collect()),
+            // A `[workspace.dependencies]` entry can't itself be `workspace = true`, and if the
+            // workspace has no matching entry at all there's nothing further to go on.
+            Some(cargo_toml::Dependency::Inherited(_)) | None => (DepSource::Unknown, true, HashSet::new()),
+            };
+        let mut features = ws_features;
+        features.extend(resolved_features.dep_features(depname));
         for f in &details.features
         {
             features.insert(f.clone());
         }
+        // See the known limitation documented on `ActiveDependency::include_default_features`:
+        // `cargo_toml` doesn't expose a member-level `default-features` override here, so the
+        // workspace's value always wins.
         ActiveDependency {
-            source: source,
-            include_default_features: false,	// This depends on if the workspace asked for default features
-            features: features,
+            kind,
+            source,
+            include_default_features: ws_default_features,
+            features,
+            resolved_version: None,
+            checksum: None,
             }
 		},
     cargo_toml::Dependency::Detailed(details) => {
-        if details.optional && std::env::var_os(format!("CARGO_FEATURE_{}", depname)).is_none() {
+        if details.optional && !resolved_features.is_dep_active(depname) {
             return None;
         }
-        let source = 
-            if let Some(ref version_str) = details.version {
-                DepSource::CratesIo(version_str.clone())
-            }
-            else if let Some(ref path) = details.path {
-                DepSource::Path(path.clone())
-            }
-            else if let Some(ref url) = details.git {
-                DepSource::Git {
-                    url: url.clone(),
-                    revision: if let Some(ref rev) = details.rev {
-                            GitRev::Revision(rev.clone())
-                        }
-                        else if let Some(ref tag) = details.tag {
-                            GitRev::Tag(tag.clone())
-                        }
-                        else if let Some(ref branch) = details.branch {
-                            GitRev::Branch(branch.clone())
-                        }
-                        else {
-                            GitRev::Master
-                        },
-                    }
-            }
-            else {
-                DepSource::Unknown
-            };
-        let mut features = dep_features.get(depname).cloned().unwrap_or(HashSet::new());
+        let source = detailed_source(details);
+        let mut features = resolved_features.dep_features(depname);
         for f in &details.features
         {
             features.insert(f.clone());
         }
         ActiveDependency {
-            source: source,
+            kind,
+            source,
             include_default_features: details.default_features,
-            features: features,
+            features,
+            resolved_version: None,
+            checksum: None,
             }
         },
     })
 }
 
+/// An entry in a `[features]` list - see [`parse_feature_value`]
+#[derive(Debug, Clone)]
+enum FeatureValue
+{
+    /// A plain feature of this crate, e.g. `"foo"` - recurse into it
+    Feature(String),
+    /// `"dep:name"` - activates the optional dependency `name` without enabling a feature on it
+    Dep(String),
+    /// `"name/feat"` (or weak `"name?/feat"`) - activates `feat` on dependency `name`. A non-weak
+    /// entry also activates `name` itself (implying it, if optional); a weak one only ever
+    /// contributes the feature, and never activates the dependency on its own.
+    DepFeature { dep: String, feature: String, weak: bool },
+}
+
+/// Classify one entry of a `[features]` sub-list, per Cargo's `dep:`/`?/`-aware syntax
+fn parse_feature_value(s: &str) -> FeatureValue
+{
+    if let Some(dep) = s.strip_prefix("dep:") {
+        return FeatureValue::Dep(dep.to_string());
+    }
+    if let Some((dep, feature)) = s.split_once('/') {
+        return match dep.strip_suffix('?')
+            {
+            Some(dep) => FeatureValue::DepFeature { dep: dep.to_string(), feature: feature.to_string(), weak: true },
+            None => FeatureValue::DepFeature { dep: dep.to_string(), feature: feature.to_string(), weak: false },
+            };
+    }
+    FeatureValue::Feature(s.to_string())
+}
+
+/// Result of resolving the `[features]` graph against the active `CARGO_FEATURE_*` flags
+struct ResolvedFeatures
+{
+    /// Optional dependencies explicitly activated via `dep:name` or a non-weak `name/feat`
+    activated_deps: HashSet<String>,
+    /// Additional features to enable on a given dependency, from `name/feat` and `name?/feat`
+    dep_features: HashMap<String, HashSet<String>>,
+}
+impl ResolvedFeatures
+{
+    /// Is the (possibly optional) dependency `depname` activated?
+    ///
+    /// True either because some active feature named it via `dep:`/`name/feat`, or because Cargo
+    /// enabled its implicit same-named feature (the case when no `dep:` syntax is used anywhere).
+    fn is_dep_active(&self, depname: &str) -> bool
+    {
+        self.activated_deps.contains(depname) || std::env::var_os(cargo_feature_env(depname)).is_some()
+    }
+    fn dep_features(&self, depname: &str) -> HashSet<String>
+    {
+        self.dep_features.get(depname).cloned().unwrap_or_default()
+    }
+}
+
+/// The `CARGO_FEATURE_*` env var name Cargo uses for a feature (or optional-dependency-as-feature)
+fn cargo_feature_env(name: &str) -> String
+{
+    format!("CARGO_FEATURE_{}", name.to_uppercase().replace('-', "_"))
+}
+
+/// Resolve the crate's `[features]` table against the currently-active `CARGO_FEATURE_*` flags
+///
+/// Starts from the features Cargo has already activated, transitively closes over plain
+/// feature-of-this-crate entries to a fixed point, then - only once that's settled - works out
+/// which optional dependencies are activated and what per-dependency features apply.
+fn resolve_features(feature_defs: &std::collections::BTreeMap<String, Vec<String>>) -> ResolvedFeatures
+{
+    let mut active = HashSet::<String>::new();
+    let mut queue: Vec<String> = feature_defs.keys()
+        .filter(|f| std::env::var_os(cargo_feature_env(f)).is_some())
+        .cloned()
+        .collect();
+    while let Some(f) = queue.pop()
+    {
+        if !active.insert(f.clone()) {
+            continue;
+        }
+        if let Some(values) = feature_defs.get(&f) {
+            for v in values {
+                if let FeatureValue::Feature(sub) = parse_feature_value(v) {
+                    queue.push(sub);
+                }
+            }
+        }
+    }
+
+    let mut activated_deps = HashSet::new();
+    let mut dep_features = HashMap::<String, HashSet<String>>::new();
+    for f in &active
+    {
+        let Some(values) = feature_defs.get(f) else { continue };
+        for v in values
+        {
+            match parse_feature_value(v)
+            {
+            FeatureValue::Feature(_) => {},
+            FeatureValue::Dep(dep) => { activated_deps.insert(dep); },
+            FeatureValue::DepFeature { dep, feature, weak } => {
+                if !weak {
+                    activated_deps.insert(dep.clone());
+                }
+                dep_features.entry(dep).or_default().insert(feature);
+                },
+            }
+        }
+    }
+    ResolvedFeatures { activated_deps, dep_features }
+}
+
+/// Derive a [`DepSource`] from a detailed dependency table (used both for member dependencies
+/// and for resolving entries found in `[workspace.dependencies]`)
+fn detailed_source(details: &cargo_toml::DependencyDetail) -> DepSource
+{
+    if let Some(ref version_str) = details.version {
+        DepSource::CratesIo(version_str.clone())
+    }
+    else if let Some(ref path) = details.path {
+        DepSource::Path(path.clone())
+    }
+    else if let Some(ref url) = details.git {
+        DepSource::Git {
+            url: url.clone(),
+            revision: if let Some(ref rev) = details.rev {
+                    GitRev::Revision(rev.clone())
+                }
+                else if let Some(ref tag) = details.tag {
+                    GitRev::Tag(tag.clone())
+                }
+                else if let Some(ref branch) = details.branch {
+                    GitRev::Branch(branch.clone())
+                }
+                else {
+                    GitRev::Master
+                },
+            }
+    }
+    else {
+        DepSource::Unknown
+    }
+}
+
 /// Check `cfg()`-style targets
 fn check_cfg_root(ml: &syn::MetaList) -> Option<bool>
 {
@@ -221,7 +546,12 @@ fn check_cfg(m: &syn::NestedMeta) -> Option<bool>
         };
     Some(match m
     {
-    syn::Meta::Path(_) => return None,
+    // A bare flag, e.g. `cfg(unix)`, `cfg(windows)`, `cfg(test)` - Cargo sets the matching
+    // `CARGO_CFG_<NAME>` env var (to an empty string) iff the flag is set for the current target
+    syn::Meta::Path(path) => {
+        let i = path.get_ident()?;
+        std::env::var_os(format!("CARGO_CFG_{}", i.to_string().to_uppercase())).is_some()
+        },
     syn::Meta::List(ml) => {
         let i = ml.path.get_ident()?;
         if i == "any" {
@@ -263,8 +593,15 @@ fn check_cfg(m: &syn::NestedMeta) -> Option<bool>
                 return None;
                 },
             };
-        let ev = std::env::var(format!("CARGO_CFG_{}", i));
-        ev == Ok(v)
+        // Some keys (notably `target_feature`, and `target_family`) can be set to a
+        // comma-separated list of values - match if the requested value is any one of them
+        let key = format!("CARGO_CFG_{}", i.to_string().to_uppercase());
+        match std::env::var(&key)
+        {
+        Ok(ev) if i == "target_feature" || i == "target_family" => ev.split(',').any(|f| f == v),
+        Ok(ev) => ev == v,
+        Err(_) => false,
+        }
         },
     })
 }